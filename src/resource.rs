@@ -0,0 +1,76 @@
+#[cfg(unix)]
+use nix::sys::resource::{getrlimit, setrlimit, Resource};
+use std::fmt;
+
+/// Options controlling process-wide resource limits.
+#[derive(Debug, structopt::StructOpt)]
+pub struct ResourceOptions {
+    /// If given, raises the soft RLIMIT_NOFILE (maximum number of open file
+    /// descriptors) towards the given value, clamped to the hard limit.
+    /// Useful when the TCP side acts as a listener accepting many simultaneous
+    /// UDP-over-TCP sessions.
+    #[cfg(unix)]
+    #[structopt(long = "nofile")]
+    pub nofile: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum RaiseNofileLimitError {
+    /// Failed to get RLIMIT_NOFILE
+    #[cfg(unix)]
+    Get(nix::Error),
+
+    /// Failed to set RLIMIT_NOFILE
+    #[cfg(unix)]
+    Set(nix::Error),
+}
+
+impl fmt::Display for RaiseNofileLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use RaiseNofileLimitError::*;
+        match self {
+            #[cfg(unix)]
+            Get(_) => "Failed to get RLIMIT_NOFILE",
+            #[cfg(unix)]
+            Set(_) => "Failed to set RLIMIT_NOFILE",
+        }
+        .fmt(f)
+    }
+}
+
+impl std::error::Error for RaiseNofileLimitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use RaiseNofileLimitError::*;
+        match self {
+            #[cfg(unix)]
+            Get(e) => Some(e),
+            #[cfg(unix)]
+            Set(e) => Some(e),
+        }
+    }
+}
+
+/// Raises the soft RLIMIT_NOFILE towards `target`, clamped to the current hard
+/// limit. Does nothing if the current soft limit is already at or above
+/// `target`, or if `target` is `None`.
+#[cfg(unix)]
+pub fn raise_nofile_limit(target: Option<u64>) -> Result<(), RaiseNofileLimitError> {
+    let target = match target {
+        Some(target) => target,
+        None => return Ok(()),
+    };
+    let (soft_before, hard) = getrlimit(Resource::RLIMIT_NOFILE).map_err(RaiseNofileLimitError::Get)?;
+    let soft_after = target.min(hard);
+    log::debug!("RLIMIT_NOFILE before: soft={}, hard={}", soft_before, hard);
+    if soft_after > soft_before {
+        setrlimit(Resource::RLIMIT_NOFILE, soft_after, hard).map_err(RaiseNofileLimitError::Set)?;
+    }
+    let (soft_after, hard) = getrlimit(Resource::RLIMIT_NOFILE).map_err(RaiseNofileLimitError::Get)?;
+    log::debug!("RLIMIT_NOFILE after: soft={}, hard={}", soft_after, hard);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn raise_nofile_limit(_target: Option<u64>) -> Result<(), RaiseNofileLimitError> {
+    Ok(())
+}
@@ -4,6 +4,7 @@ use std::fmt;
 use std::io;
 #[cfg(target_os = "linux")]
 use std::os::unix::io::AsRawFd;
+use tokio::net::TcpSocket;
 use tokio::net::TcpStream;
 
 /// Options to apply to the TCP socket involved in the tunneling.
@@ -29,6 +30,61 @@ pub struct TcpOptions {
     #[cfg(target_os = "linux")]
     #[structopt(long = "fwmark")]
     pub fwmark: Option<u32>,
+
+    /// If given, enables SO_KEEPALIVE on the TCP socket and sets TCP_KEEPIDLE
+    /// to the given number of idle seconds before the first keepalive probe is sent.
+    #[cfg(target_os = "linux")]
+    #[structopt(long = "keepalive-time")]
+    pub keepalive_time: Option<u32>,
+
+    /// If given, enables SO_KEEPALIVE on the TCP socket and sets TCP_KEEPINTVL
+    /// to the given number of seconds between keepalive probes.
+    #[cfg(target_os = "linux")]
+    #[structopt(long = "keepalive-interval")]
+    pub keepalive_interval: Option<u32>,
+
+    /// If given, enables SO_KEEPALIVE on the TCP socket and sets TCP_KEEPCNT
+    /// to the given number of unacknowledged probes to send before considering
+    /// the connection dead.
+    #[cfg(target_os = "linux")]
+    #[structopt(long = "keepalive-count")]
+    pub keepalive_count: Option<u32>,
+
+    /// If given, enables TCP_FASTOPEN on the TCP socket. On the connecting side
+    /// this lets the first piece of payload ride along in the SYN, saving a
+    /// round trip every time the TCP carrier connection is re-established. On
+    /// the listening side this enables the Fast Open queue.
+    #[cfg(target_os = "linux")]
+    #[structopt(long = "fast-open")]
+    pub fast_open: bool,
+
+    /// If given, sets the IP_TOS (or IPV6_TCLASS for IPv6 sockets) field on the
+    /// TCP socket to the given value. The value is written unshifted as the whole
+    /// ToS/traffic class byte, so the DSCP is its upper six bits. Lets the tunnel cooperate with
+    /// differentiated queueing on the path for latency-sensitive UDP traffic.
+    #[cfg(target_os = "linux")]
+    #[structopt(long = "dscp")]
+    pub dscp: Option<u8>,
+
+    /// If given, sets the TCP_USER_TIMEOUT option, in milliseconds, on the TCP socket.
+    /// Specifies how long transmitted data may remain unacknowledged before the kernel
+    /// forcibly closes the connection.
+    #[cfg(target_os = "linux")]
+    #[structopt(long = "user-timeout")]
+    pub user_timeout: Option<u32>,
+
+    /// If given, sets the SO_REUSEADDR option on the listening TCP socket, before
+    /// it is bound. Allows a restarted process to reclaim a port stuck in
+    /// TIME_WAIT.
+    #[structopt(long = "reuse-address")]
+    pub reuse_address: bool,
+
+    /// If given, sets the SO_REUSEPORT option on the listening TCP socket, before
+    /// it is bound. Allows multiple accept workers to bind the same TCP endpoint,
+    /// with the kernel load-balancing incoming connections across them.
+    #[cfg(target_os = "linux")]
+    #[structopt(long = "reuse-port")]
+    pub reuse_port: bool,
 }
 
 #[derive(Debug)]
@@ -45,6 +101,29 @@ pub enum ApplyTcpOptionsError {
     /// Failed to get/set SO_MARK
     #[cfg(target_os = "linux")]
     Mark(nix::Error),
+
+    /// Failed to get/set SO_KEEPALIVE, TCP_KEEPIDLE, TCP_KEEPINTVL or TCP_KEEPCNT
+    #[cfg(target_os = "linux")]
+    KeepAlive(nix::Error),
+
+    /// Failed to set TCP_FASTOPEN or TCP_FASTOPEN_CONNECT
+    #[cfg(target_os = "linux")]
+    FastOpen(nix::Error),
+
+    /// Failed to get/set IP_TOS or IPV6_TCLASS
+    #[cfg(target_os = "linux")]
+    Tos(nix::Error),
+
+    /// Failed to get/set TCP_USER_TIMEOUT
+    #[cfg(target_os = "linux")]
+    UserTimeout(nix::Error),
+
+    /// Failed to set SO_REUSEADDR
+    ReuseAddr(io::Error),
+
+    /// Failed to set SO_REUSEPORT
+    #[cfg(target_os = "linux")]
+    ReusePort(io::Error),
 }
 
 impl fmt::Display for ApplyTcpOptionsError {
@@ -56,6 +135,17 @@ impl fmt::Display for ApplyTcpOptionsError {
             SendBuffer(_) => "Failed to get/set TCP_SNDBUF",
             #[cfg(target_os = "linux")]
             Mark(_) => "Failed to get/set SO_MARK",
+            #[cfg(target_os = "linux")]
+            KeepAlive(_) => "Failed to get/set SO_KEEPALIVE, TCP_KEEPIDLE, TCP_KEEPINTVL or TCP_KEEPCNT",
+            #[cfg(target_os = "linux")]
+            FastOpen(_) => "Failed to set TCP_FASTOPEN or TCP_FASTOPEN_CONNECT",
+            #[cfg(target_os = "linux")]
+            Tos(_) => "Failed to get/set IP_TOS or IPV6_TCLASS",
+            #[cfg(target_os = "linux")]
+            UserTimeout(_) => "Failed to get/set TCP_USER_TIMEOUT",
+            ReuseAddr(_) => "Failed to set SO_REUSEADDR",
+            #[cfg(target_os = "linux")]
+            ReusePort(_) => "Failed to set SO_REUSEPORT",
         }
         .fmt(f)
     }
@@ -70,43 +160,156 @@ impl std::error::Error for ApplyTcpOptionsError {
             SendBuffer(e) => Some(e),
             #[cfg(target_os = "linux")]
             Mark(e) => Some(e),
+            #[cfg(target_os = "linux")]
+            KeepAlive(e) => Some(e),
+            #[cfg(target_os = "linux")]
+            FastOpen(e) => Some(e),
+            #[cfg(target_os = "linux")]
+            Tos(e) => Some(e),
+            #[cfg(target_os = "linux")]
+            UserTimeout(e) => Some(e),
+            ReuseAddr(e) => Some(e),
+            #[cfg(target_os = "linux")]
+            ReusePort(e) => Some(e),
         }
     }
 }
 
-/// Applies the given options to the given TCP socket.
-pub fn apply(tcp_stream: &TcpStream, options: &TcpOptions) -> Result<(), ApplyTcpOptionsError> {
-    tcp_stream
-        .set_nodelay(options.nodelay)
-        .map_err(ApplyTcpOptionsError::NoDelay)?;
-    log::debug!(
-        "TCP_NODELAY: {}",
-        tcp_stream
-            .nodelay()
-            .map_err(ApplyTcpOptionsError::NoDelay)?
-    );
+/// Queue length to use for TCP_FASTOPEN on listening sockets.
+#[cfg(target_os = "linux")]
+const TCP_FASTOPEN_QUEUE_LEN: i32 = 128;
+
+/// `TCP_FASTOPEN_CONNECT`, from Linux's `<netinet/tcp.h>`. Not exposed as a
+/// constant by every version of the `libc` crate.
+#[cfg(target_os = "linux")]
+const TCP_FASTOPEN_CONNECT: libc::c_int = 30;
+
+/// Sets a single `i32` socket option via a raw `setsockopt(2)` call.
+///
+/// Neither `TCP_FASTOPEN` (listen-side queue length) nor
+/// `TCP_FASTOPEN_CONNECT` (client-side) have a stable wrapper across the
+/// `nix` versions this crate supports, so they're set directly through
+/// `libc` instead of `nix::sys::socket::setsockopt`.
+#[cfg(target_os = "linux")]
+fn setsockopt_i32(
+    fd: std::os::unix::io::RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: i32,
+) -> nix::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const i32 as *const libc::c_void,
+            std::mem::size_of::<i32>() as libc::socklen_t,
+        )
+    };
+    nix::errno::Errno::result(ret).map(drop)
+}
+
+/// Applies the SO_RCVBUF/SO_SNDBUF options to a not yet connected/bound TCP
+/// socket. `TcpSocket` (unlike `TcpStream`) is where these accessors live, so
+/// this runs as part of the pre-connect/pre-bind phase rather than `apply()`.
+fn apply_buffer_sizes(
+    tcp_socket: &TcpSocket,
+    options: &TcpOptions,
+) -> Result<(), ApplyTcpOptionsError> {
     if let Some(recv_buffer_size) = options.recv_buffer_size {
-        tcp_stream
-            .set_recv_buffer_size(recv_buffer_size)
+        tcp_socket
+            .set_recv_buffer_size(recv_buffer_size as u32)
             .map_err(ApplyTcpOptionsError::RecvBuffer)?;
     }
     log::debug!(
         "SO_RCVBUF: {}",
-        tcp_stream
+        tcp_socket
             .recv_buffer_size()
             .map_err(ApplyTcpOptionsError::RecvBuffer)?
     );
     if let Some(send_buffer_size) = options.send_buffer_size {
-        tcp_stream
-            .set_send_buffer_size(send_buffer_size)
+        tcp_socket
+            .set_send_buffer_size(send_buffer_size as u32)
             .map_err(ApplyTcpOptionsError::SendBuffer)?;
     }
     log::debug!(
         "SO_SNDBUF: {}",
-        tcp_stream
+        tcp_socket
             .send_buffer_size()
             .map_err(ApplyTcpOptionsError::SendBuffer)?
     );
+    Ok(())
+}
+
+/// Applies the options that must be set on the listening TCP socket before
+/// `bind()` is called on it.
+pub fn apply_pre_bind(
+    tcp_socket: &TcpSocket,
+    options: &TcpOptions,
+) -> Result<(), ApplyTcpOptionsError> {
+    apply_buffer_sizes(tcp_socket, options)?;
+    tcp_socket
+        .set_reuseaddr(options.reuse_address)
+        .map_err(ApplyTcpOptionsError::ReuseAddr)?;
+    #[cfg(target_os = "linux")]
+    {
+        tcp_socket
+            .set_reuseport(options.reuse_port)
+            .map_err(ApplyTcpOptionsError::ReusePort)?;
+    }
+    Ok(())
+}
+
+/// Applies the options that must be set on the TCP socket before `connect()` is
+/// called on it, i.e. before the TCP handshake happens.
+pub fn apply_pre_connect(
+    tcp_socket: &TcpSocket,
+    options: &TcpOptions,
+) -> Result<(), ApplyTcpOptionsError> {
+    apply_buffer_sizes(tcp_socket, options)?;
+    #[cfg(target_os = "linux")]
+    {
+        if options.fast_open {
+            let fd = tcp_socket.as_raw_fd();
+            setsockopt_i32(fd, libc::IPPROTO_TCP, TCP_FASTOPEN_CONNECT, 1)
+                .map_err(ApplyTcpOptionsError::FastOpen)?;
+        }
+    }
+    Ok(())
+}
+
+/// Applies the options that must be set on the TCP socket before `listen()` is
+/// called on it.
+pub fn apply_pre_listen(
+    tcp_socket: &TcpSocket,
+    options: &TcpOptions,
+) -> Result<(), ApplyTcpOptionsError> {
+    #[cfg(target_os = "linux")]
+    {
+        if options.fast_open {
+            let fd = tcp_socket.as_raw_fd();
+            setsockopt_i32(fd, libc::IPPROTO_TCP, libc::TCP_FASTOPEN, TCP_FASTOPEN_QUEUE_LEN)
+                .map_err(ApplyTcpOptionsError::FastOpen)?;
+        }
+    }
+    Ok(())
+}
+
+/// Applies the options that are set on the connected TCP socket: TCP_NODELAY
+/// and, on Linux, SO_MARK, the keepalive options, IP_TOS/IPV6_TCLASS and
+/// TCP_USER_TIMEOUT. The buffer sizes, SO_REUSEADDR/SO_REUSEPORT and
+/// TCP_FASTOPEN are not applied here; `apply_pre_bind`, `apply_pre_connect`
+/// and `apply_pre_listen` apply them.
+pub fn apply(tcp_stream: &TcpStream, options: &TcpOptions) -> Result<(), ApplyTcpOptionsError> {
+    tcp_stream
+        .set_nodelay(options.nodelay)
+        .map_err(ApplyTcpOptionsError::NoDelay)?;
+    log::debug!(
+        "TCP_NODELAY: {}",
+        tcp_stream
+            .nodelay()
+            .map_err(ApplyTcpOptionsError::NoDelay)?
+    );
     #[cfg(target_os = "linux")]
     {
         let fd = tcp_stream.as_raw_fd();
@@ -117,6 +320,58 @@ pub fn apply(tcp_stream: &TcpStream, options: &TcpOptions) -> Result<(), ApplyTc
             "SO_MARK: {}",
             getsockopt(fd, sockopt::Mark).map_err(ApplyTcpOptionsError::Mark)?
         );
+        let keepalive_requested = options.keepalive_time.is_some()
+            || options.keepalive_interval.is_some()
+            || options.keepalive_count.is_some();
+        if keepalive_requested {
+            setsockopt(fd, sockopt::KeepAlive, &true).map_err(ApplyTcpOptionsError::KeepAlive)?;
+            if let Some(keepalive_time) = options.keepalive_time {
+                setsockopt(fd, sockopt::TcpKeepIdle, &keepalive_time)
+                    .map_err(ApplyTcpOptionsError::KeepAlive)?;
+            }
+            if let Some(keepalive_interval) = options.keepalive_interval {
+                setsockopt(fd, sockopt::TcpKeepInterval, &keepalive_interval)
+                    .map_err(ApplyTcpOptionsError::KeepAlive)?;
+            }
+            if let Some(keepalive_count) = options.keepalive_count {
+                setsockopt(fd, sockopt::TcpKeepCount, &keepalive_count)
+                    .map_err(ApplyTcpOptionsError::KeepAlive)?;
+            }
+        }
+        log::debug!(
+            "SO_KEEPALIVE: {}",
+            getsockopt(fd, sockopt::KeepAlive).map_err(ApplyTcpOptionsError::KeepAlive)?
+        );
+        let local_addr = tcp_stream
+            .local_addr()
+            .expect("a connected TCP socket always has a local address");
+        if local_addr.is_ipv4() {
+            if let Some(dscp) = options.dscp {
+                setsockopt(fd, sockopt::IpTos, &i32::from(dscp))
+                    .map_err(ApplyTcpOptionsError::Tos)?;
+            }
+            log::debug!(
+                "IP_TOS: {}",
+                getsockopt(fd, sockopt::IpTos).map_err(ApplyTcpOptionsError::Tos)?
+            );
+        } else {
+            if let Some(dscp) = options.dscp {
+                setsockopt(fd, sockopt::Ipv6TClass, &i32::from(dscp))
+                    .map_err(ApplyTcpOptionsError::Tos)?;
+            }
+            log::debug!(
+                "IPV6_TCLASS: {}",
+                getsockopt(fd, sockopt::Ipv6TClass).map_err(ApplyTcpOptionsError::Tos)?
+            );
+        }
+        if let Some(user_timeout) = options.user_timeout {
+            setsockopt(fd, sockopt::TcpUserTimeout, &user_timeout)
+                .map_err(ApplyTcpOptionsError::UserTimeout)?;
+        }
+        log::debug!(
+            "TCP_USER_TIMEOUT: {}",
+            getsockopt(fd, sockopt::TcpUserTimeout).map_err(ApplyTcpOptionsError::UserTimeout)?
+        );
     }
     Ok(())
 }